@@ -1,7 +1,10 @@
-use log::info;
+use log::{error, info};
+use minisign_verify::{PublicKey, Signature};
+use rand::Rng;
 use reqwest::Client;
 use semver::Version;
 use serde::Deserialize;
+use sha2::{Digest, Sha256};
 use std::env;
 use std::io::{Error, ErrorKind};
 use std::path::Path;
@@ -18,18 +21,71 @@ pub struct Manifest {
     pub version: String,
     pub date: Option<String>,
     pub author: Option<String>,
+    pub signature: Option<String>,
+    pub sha256: Option<String>,
 }
 
-pub const MANIFEST_URL: &str = "https://os.druid.garden/manifest.yaml";
 pub const BIN_PATH: &str = "/usr/bin/druid-garden-os.app";
-pub const BACKUP_PATH: &str = "/usr/bin/druid-garden-os.app.bak";
 pub const TMP_PATH: &str = "/tmp/druid-garden-os.app";
 pub const SERVICE_NAME: &str = "druid_garden_os";
 pub const UPDATER_SERVICE_NAME: &str = "druid_garden_edge_updater";
 
-pub async fn fetch_manifest(client: &Client) -> Result<Manifest, Error> {
+/// Number of generational backups kept under `BIN_PATH`'s directory; older
+/// ones are pruned on each successful swap.
+pub const MAX_BACKUPS: usize = 3;
+/// How long after a successful `systemctl start` we keep polling
+/// `is-active` before declaring the update healthy.
+pub const HEALTH_SETTLE_SECS: u64 = 10;
+/// Interval between health polls within the settle window.
+pub const HEALTH_POLL_INTERVAL_SECS: u64 = 1;
+
+/// Release track used when no channel has been configured yet.
+pub const DEFAULT_CHANNEL: &str = "stable";
+/// Where the device's chosen release channel is persisted across reboots.
+pub const CHANNEL_CONFIG_PATH: &str = "/etc/druid-garden-os/channel";
+
+/// Minisign public key trusted to sign release binaries, generated and
+/// held offline by the Druid Garden release team.
+pub const PUBLIC_KEY: &str =
+    "RWQf6LRCGA9i53mlYecO4IzT51TGPpvKikTxqdQlTI+7ki2RE9gG4Z+J";
+
+/// Default `--watch` polling interval, in seconds.
+pub const DEFAULT_INTERVAL_SECS: u64 = 3600;
+/// Upper bound on the random jitter added to each `--watch` interval, so
+/// edge devices don't all poll `os.druid.garden` in lockstep.
+pub const MAX_JITTER_SECS: u64 = 300;
+
+/// Sleeps for `interval` plus a random jitter of up to [`MAX_JITTER_SECS`].
+pub async fn sleep_with_jitter(interval: Duration) {
+    let jitter = rand::thread_rng().gen_range(0..=MAX_JITTER_SECS);
+    sleep(interval + Duration::from_secs(jitter)).await;
+}
+
+/// Reads the device's persisted release channel from [`CHANNEL_CONFIG_PATH`],
+/// falling back to [`DEFAULT_CHANNEL`] when unset so a fresh device stays on
+/// the stable track until opted in.
+pub async fn read_channel() -> String {
+    fs::read_to_string(CHANNEL_CONFIG_PATH)
+        .await
+        .ok()
+        .map(|s| s.trim().to_string())
+        .filter(|s| !s.is_empty())
+        .unwrap_or_else(|| DEFAULT_CHANNEL.to_string())
+}
+
+/// Persists `channel` to [`CHANNEL_CONFIG_PATH`] so the device stays on this
+/// track across reboots.
+pub async fn write_channel(channel: &str) -> Result<(), Error> {
+    if let Some(parent) = Path::new(CHANNEL_CONFIG_PATH).parent() {
+        fs::create_dir_all(parent).await?;
+    }
+    fs::write(CHANNEL_CONFIG_PATH, channel).await
+}
+
+pub async fn fetch_manifest(client: &Client, channel: &str) -> Result<Manifest, Error> {
+    let manifest_url = format!("https://os.druid.garden/{channel}/manifest.yaml");
     let response = client
-        .get(MANIFEST_URL)
+        .get(manifest_url)
         .send()
         .await
         .map_err(|e| Error::new(ErrorKind::Other, e))?
@@ -41,12 +97,70 @@ pub async fn fetch_manifest(client: &Client) -> Result<Manifest, Error> {
     serde_yaml::from_str(&response).map_err(|e| Error::new(ErrorKind::Other, e))
 }
 
-pub async fn swap_binaries() -> Result<(), Error> {
-    if Path::new(BACKUP_PATH).exists() {
-        let _ = fs::remove_file(BACKUP_PATH).await;
+/// Path of the generational backup for `version`, e.g.
+/// `/usr/bin/druid-garden-os.app.1.2.3.bak`.
+fn backup_path(version: &Version) -> String {
+    format!("{BIN_PATH}.{version}.bak")
+}
+
+/// Lists existing generational backups, newest first.
+async fn list_backups() -> Result<Vec<std::path::PathBuf>, Error> {
+    let dir = Path::new(BIN_PATH).parent().unwrap_or_else(|| Path::new("/"));
+    let prefix = Path::new(BIN_PATH)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let mut entries = fs::read_dir(dir).await?;
+    let mut backups = Vec::new();
+    while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name();
+        let name = name.to_string_lossy();
+        if name.starts_with(&prefix) && name.ends_with(".bak") {
+            let modified = entry.metadata().await?.modified()?;
+            backups.push((entry.path(), modified));
+        }
+    }
+    backups.sort_by_key(|b| std::cmp::Reverse(b.1));
+    Ok(backups.into_iter().map(|(path, _)| path).collect())
+}
+
+/// Removes all but the [`MAX_BACKUPS`] most recent generational backups.
+async fn prune_backups() -> Result<(), Error> {
+    for stale in list_backups().await?.into_iter().skip(MAX_BACKUPS) {
+        info!("Pruning old backup {}", stale.display());
+        let _ = fs::remove_file(&stale).await;
     }
-    fs::rename(BIN_PATH, BACKUP_PATH).await?;
-    fs::copy(TMP_PATH, BIN_PATH).await.map(|_| ())
+    Ok(())
+}
+
+/// Backs up the currently installed `from_version` binary under a
+/// per-version generation, then swaps in the freshly downloaded one.
+pub async fn swap_binaries(from_version: &Version) -> Result<(), Error> {
+    fs::copy(BIN_PATH, backup_path(from_version)).await?;
+    fs::copy(TMP_PATH, BIN_PATH).await?;
+    prune_backups().await
+}
+
+/// Restores the most recent generational backup over `BIN_PATH` and
+/// restarts the service. Intended both as the failure path inside
+/// [`try_start_with_rollback`] and as the standalone `--rollback` command.
+///
+/// Returns `Ok(false)` rather than an `Err` if the restore itself succeeds
+/// but the subsequent restart fails, so callers can treat "rolled back but
+/// still down" the same uniform way as any other failed start attempt.
+pub async fn rollback() -> Result<bool, Error> {
+    let backups = list_backups().await?;
+    let latest = backups.first().ok_or(Error::new(
+        ErrorKind::NotFound,
+        "No backups available to roll back to",
+    ))?;
+    info!("Rolling back to {}", latest.display());
+    run_systemctl("stop").await.ok();
+    fs::remove_file(BIN_PATH).await.ok();
+    fs::copy(latest, BIN_PATH).await?;
+    set_executable_bit(BIN_PATH).await?;
+    Ok(run_systemctl("start").await.is_ok())
 }
 
 pub async fn get_binary_version(path: &str) -> Option<Version> {
@@ -73,32 +187,171 @@ pub async fn run_systemctl(action: &str) -> Result<(), Error> {
     Ok(())
 }
 
-pub async fn download_file(client: &Client, path: &str, download_url: &str) -> Result<(), Error> {
-    info!("Downloading from {download_url} to {path}");
-    let mut resp = client
-        .get(download_url)
+/// Maximum number of attempts `download_file` will make before giving up and
+/// letting the outer update cycle decide whether to try again later.
+pub const MAX_DOWNLOAD_RETRIES: u32 = 5;
+
+/// Path of the sidecar file that records which `download_url` a partial
+/// `path` belongs to, so a stale leftover from a previous target (e.g. the
+/// last version's binary, still sitting at `TMP_PATH` after a successful
+/// update) is never mistaken for an in-progress download of a new one.
+fn download_meta_path(path: &str) -> String {
+    format!("{path}.meta")
+}
+
+/// Returns the byte offset to resume `path` from. If `path` (plus its
+/// sidecar) isn't a partial download of this exact `download_url`, any
+/// stale leftovers are discarded and the download starts from scratch.
+async fn resume_offset(path: &str, download_url: &str) -> Result<u64, Error> {
+    let meta_path = download_meta_path(path);
+    let belongs_to_target = fs::read_to_string(&meta_path)
+        .await
+        .map(|saved_url| saved_url == download_url)
+        .unwrap_or(false);
+    if !belongs_to_target {
+        let _ = fs::remove_file(path).await;
+        let _ = fs::remove_file(&meta_path).await;
+        return Ok(0);
+    }
+    Ok(fs::metadata(path).await.map(|m| m.len()).unwrap_or(0))
+}
+
+/// Downloads `download_url` to `path`, hashing the bytes as they're written
+/// so callers can detect a truncated or corrupted transfer without a second
+/// pass over the file. Returns the lowercase hex SHA256 digest.
+///
+/// Resumes a partial `path` left over from a previous attempt at this exact
+/// `download_url` via an HTTP `Range` request, falling back to a full
+/// restart if the partial belongs to a different target or the server
+/// answers with `200 OK`/`416 Range Not Satisfiable` instead of
+/// `206 Partial Content`. Retries with exponential backoff, making at most
+/// [`MAX_DOWNLOAD_RETRIES`] attempts in total.
+///
+/// `on_progress` is called after every chunk is written with
+/// `(downloaded_bytes, total_bytes)`, so callers can report progress on a
+/// long transfer instead of only learning the outcome once it's done.
+/// `total_bytes` is 0 when the response carries no `Content-Length`.
+pub async fn download_file(
+    client: &Client,
+    path: &str,
+    download_url: &str,
+    mut on_progress: impl FnMut(u64, u64),
+) -> Result<String, Error> {
+    let mut downloaded = resume_offset(path, download_url).await?;
+    let mut hasher = Sha256::new();
+    if downloaded > 0 {
+        hasher.update(&fs::read(path).await?);
+    }
+    fs::write(download_meta_path(path), download_url).await?;
+
+    let mut attempt = 0;
+    loop {
+        match download_attempt(
+            client,
+            path,
+            download_url,
+            &mut downloaded,
+            &mut hasher,
+            &mut on_progress,
+        )
+        .await
+        {
+            Ok(total) => {
+                info!("Downloaded {downloaded}/{total} bytes from {download_url}");
+                let _ = fs::remove_file(download_meta_path(path)).await;
+                return Ok(format!("{:x}", hasher.finalize()));
+            }
+            Err(e) => {
+                attempt += 1;
+                if attempt >= MAX_DOWNLOAD_RETRIES {
+                    return Err(e);
+                }
+                let backoff = Duration::from_secs(2u64.pow(attempt));
+                info!("Download attempt {attempt} failed ({e}); retrying in {backoff:?}");
+                sleep(backoff).await;
+            }
+        }
+    }
+}
+
+/// Performs a single download attempt, resuming from `*downloaded` bytes if
+/// the server honors the `Range` header. Returns the total size of the file
+/// once complete.
+async fn download_attempt(
+    client: &Client,
+    path: &str,
+    download_url: &str,
+    downloaded: &mut u64,
+    hasher: &mut Sha256,
+    on_progress: &mut impl FnMut(u64, u64),
+) -> Result<u64, Error> {
+    let mut request = client.get(download_url);
+    if *downloaded > 0 {
+        request = request.header(reqwest::header::RANGE, format!("bytes={}-", downloaded));
+    }
+    let resp = request
         .send()
         .await
-        .map_err(|e| Error::new(ErrorKind::Other, e))?
-        .error_for_status()
         .map_err(|e| Error::new(ErrorKind::Other, e))?;
-    let mut out = fs::File::create(path)
-        .await
+
+    if resp.status() == reqwest::StatusCode::RANGE_NOT_SATISFIABLE {
+        // Our Range request no longer matches what the server has at this URL
+        // (e.g. the partial file changed size out from under us). Reset so
+        // the next attempt restarts the transfer from scratch instead of
+        // retrying the same invalid range forever.
+        *downloaded = 0;
+        *hasher = Sha256::new();
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            "range not satisfiable, restarting download",
+        ));
+    }
+    let mut resp = resp
+        .error_for_status()
         .map_err(|e| Error::new(ErrorKind::Other, e))?;
+
+    let mut out = if resp.status() == reqwest::StatusCode::PARTIAL_CONTENT {
+        info!("Resuming download of {path} from byte {downloaded}");
+        fs::OpenOptions::new().append(true).open(path).await?
+    } else {
+        // Server ignored the Range header (or we had nothing to resume); start over.
+        *downloaded = 0;
+        *hasher = Sha256::new();
+        fs::File::create(path).await?
+    };
+    let total = resp.content_length().map_or(0, |len| len + *downloaded);
+
     while let Some(chunk) = resp
         .chunk()
         .await
         .map_err(|e| Error::new(ErrorKind::Other, e))?
     {
         out.write_all(&chunk).await?;
+        hasher.update(&chunk);
+        *downloaded += chunk.len() as u64;
+        on_progress(*downloaded, total);
     }
-    Ok(())
+    Ok(*downloaded)
 }
 
-pub fn get_download_url(version: &str) -> Result<String, Error> {
+/// Verifies that the file at `path` was signed by [`PUBLIC_KEY`], rejecting
+/// the download instead of letting it reach `swap_binaries`.
+pub fn verify_signature(path: &str, signature: &str) -> Result<(), Error> {
+    let public_key = PublicKey::from_base64(PUBLIC_KEY)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let signature = Signature::decode(signature)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+    let bytes = std::fs::read(path)?;
+    public_key
+        .verify(&bytes, &signature, false)
+        .map_err(|e| Error::new(ErrorKind::InvalidData, format!("signature verification failed: {e}")))
+}
+
+pub fn get_download_url(channel: &str, version: &str) -> Result<String, Error> {
     let arch = env::consts::ARCH;
     Ok(format!(
-        "https://os.druid.garden/{}/{}/druid-garden-os.app",
+        "https://os.druid.garden/{}/{}/{}/druid-garden-os.app",
+        channel,
         version,
         if arch == "x86_64" {
             "amd64"
@@ -124,19 +377,117 @@ pub async fn set_executable_bit(path: &str) -> Result<(), Error> {
     fs::set_permissions(path, perms).await
 }
 
+/// Result of a single [`run_update_cycle`] pass.
+pub enum UpdateOutcome {
+    UpToDate,
+    Updated { from: Version, to: Version },
+    Failed,
+}
+
+/// Runs one check-and-update pass: fetch the manifest, compare versions,
+/// and — if a newer build is available — download, verify, and swap it in.
+/// Shared by the one-shot and `--watch` entry points in `main` so both
+/// follow exactly the same update path.
+pub async fn run_update_cycle(client: &Client, channel: &str) -> Result<UpdateOutcome, Error> {
+    let manifest = fetch_manifest(client, channel).await?;
+    let remote_version =
+        Version::parse(&manifest.version).map_err(|e| Error::new(ErrorKind::Other, e))?;
+    info!("Found Remote version: {}", remote_version);
+    let local_version = get_binary_version(BIN_PATH)
+        .await
+        .unwrap_or_else(|| Version::new(0, 0, 0));
+    info!("Found Local version:  {}", local_version);
+    if remote_version <= local_version {
+        info!("Up to date! nothing to do.");
+        return Ok(UpdateOutcome::UpToDate);
+    }
+
+    // Download the New Binary and make executable
+    let download_url = get_download_url(channel, manifest.version.as_str())?;
+    let mut last_logged_percent = 0u64;
+    let digest = download_file(client, TMP_PATH, &download_url, |downloaded, total| {
+        if total == 0 {
+            return;
+        }
+        let percent = downloaded * 100 / total;
+        if percent >= last_logged_percent + 10 || downloaded == total {
+            info!("Download progress: {downloaded}/{total} bytes ({percent}%)");
+            last_logged_percent = percent;
+        }
+    })
+    .await?;
+    if let Some(expected) = manifest.sha256.as_deref() {
+        if !expected.eq_ignore_ascii_case(&digest) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "Downloaded binary failed checksum verification",
+            ));
+        }
+    }
+    set_executable_bit(TMP_PATH).await?;
+
+    // Verify the download was signed by us before trusting it any further
+    let signature = manifest
+        .signature
+        .as_deref()
+        .ok_or(Error::new(ErrorKind::Other, "Manifest is missing a signature"))?;
+    verify_signature(TMP_PATH, signature)?;
+
+    // Verify downloaded binary
+    let downloaded_version = get_binary_version(TMP_PATH).await.ok_or(Error::new(
+        ErrorKind::Other,
+        "Failed to read downloaded binary version",
+    ))?;
+    if downloaded_version != remote_version {
+        return Err(Error::new(
+            ErrorKind::Other,
+            "Downloaded binary version mismatch",
+        ));
+    }
+
+    // Stop old OS service
+    run_systemctl("stop").await?;
+
+    // Backup the Old binary and swap in the new one
+    swap_binaries(&local_version).await?;
+
+    // Start service with retry + rollback
+    if try_start_with_rollback().await? {
+        info!("Update successful!");
+        Ok(UpdateOutcome::Updated {
+            from: local_version,
+            to: remote_version,
+        })
+    } else {
+        error!("CRITICAL UPDATE FAILURE - PLEASE REBOOT DEVICE");
+        Ok(UpdateOutcome::Failed)
+    }
+}
+
+/// Polls `systemctl is-active` for [`HEALTH_SETTLE_SECS`], catching a
+/// binary that starts but then immediately crash-loops.
+async fn settle_and_check_health() -> bool {
+    let polls = HEALTH_SETTLE_SECS / HEALTH_POLL_INTERVAL_SECS;
+    for _ in 0..polls {
+        sleep(Duration::from_secs(HEALTH_POLL_INTERVAL_SECS)).await;
+        if run_systemctl("is-active").await.is_err() {
+            return false;
+        }
+    }
+    true
+}
+
 pub async fn try_start_with_rollback() -> Result<bool, Error> {
     for attempt in 1..=3 {
         info!("Starting service (attempt {})…", attempt);
-        if run_systemctl("start").await.is_ok() {
+        if run_systemctl("start").await.is_ok() && settle_and_check_health().await {
             return Ok(true);
         }
+        info!("Service did not settle into active, retrying…");
         sleep(Duration::from_secs(2)).await;
     }
-    info!("Rolling back to backup…");
-    fs::remove_file(BIN_PATH).await.ok();
-    fs::rename(BACKUP_PATH, BIN_PATH).await?;
-    // try once more
-    if run_systemctl("start").await.is_ok() {
+    info!("Rolling back to last known-good backup…");
+    if rollback().await? && settle_and_check_health().await {
         info!("Rollback succeeded");
         return Ok(true);
     }
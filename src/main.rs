@@ -1,13 +1,12 @@
-use dg_edge_updater::{BIN_PATH, TMP_PATH};
 use dg_edge_updater::{
-    download_file, fetch_manifest, get_binary_version, get_download_url, run_systemctl,
-    set_executable_bit, swap_binaries, try_start_with_rollback,
+    DEFAULT_INTERVAL_SECS, UpdateOutcome, read_channel, rollback, run_update_cycle,
+    sleep_with_jitter, write_channel,
 };
 use dg_logger::{DruidGardenLogger, TimestampFormat};
 use log::{Level, error, info};
 use reqwest::Client;
-use semver::Version;
 use std::io::{Error, ErrorKind};
+use std::time::Duration;
 
 #[tokio::main]
 async fn main() -> Result<(), Error> {
@@ -21,48 +20,62 @@ async fn main() -> Result<(), Error> {
         .map_err(|e| Error::new(ErrorKind::Other, format!("{e:?}")))?;
     let client = Client::new();
 
-    // Check for Updates
-    let manifest = fetch_manifest(&client).await?;
-    let remote_version =
-        Version::parse(&manifest.version).map_err(|e| Error::new(ErrorKind::Other, e))?;
-    info!("Found Remote version: {}", remote_version);
-    let local_version = get_binary_version(BIN_PATH)
-        .await
-        .unwrap_or_else(|| Version::new(0, 0, 0));
-    info!("Found Local version:  {}", local_version);
-    if remote_version <= local_version {
-        info!("Up to date! nothing to do.");
-        return Ok(());
+    let args: Vec<String> = std::env::args().collect();
+    if args.iter().any(|a| a == "--rollback") {
+        return if rollback().await? {
+            info!("Rollback complete");
+            Ok(())
+        } else {
+            error!("Rollback restored the previous binary but the service failed to start");
+            Err(Error::new(ErrorKind::Other, "Service failed to start after rollback"))
+        };
     }
+    let watch = args.iter().any(|a| a == "--watch");
+    let interval = parse_interval(&args).unwrap_or(DEFAULT_INTERVAL_SECS);
 
-    // Download the New Binary and make executable
-    let download_url = get_download_url(manifest.version.as_str())?;
-    download_file(&client, TMP_PATH, &download_url).await?;
-    set_executable_bit(TMP_PATH).await?;
+    let channel = match parse_channel(&args) {
+        Some(channel) => {
+            write_channel(&channel).await?;
+            channel
+        }
+        None => read_channel().await,
+    };
+    info!("Using release channel: {channel}");
 
-    // Verify downloaded binary
-    let downloaded_version = get_binary_version(TMP_PATH).await.ok_or(Error::new(
-        ErrorKind::Other,
-        "Failed to read downloaded binary version",
-    ))?;
-    if downloaded_version != remote_version {
-        return Err(Error::new(
-            ErrorKind::Other,
-            "Downloaded binary version mismatch",
-        ));
+    if !watch {
+        return match run_update_cycle(&client, &channel).await? {
+            UpdateOutcome::Updated { from, to } => {
+                info!("Updated from {from} to {to}");
+                Ok(())
+            }
+            UpdateOutcome::UpToDate => Ok(()),
+            UpdateOutcome::Failed => {
+                error!("CRITICAL UPDATE FAILURE - PLEASE REBOOT DEVICE");
+                Err(Error::new(ErrorKind::Other, "Update failed"))
+            }
+        };
     }
 
-    // Stop old OS service
-    run_systemctl("stop").await?;
+    info!("Running in watch mode, checking every {interval}s (+ jitter)");
+    loop {
+        match run_update_cycle(&client, &channel).await {
+            Ok(UpdateOutcome::Updated { from, to }) => info!("Updated from {from} to {to}"),
+            Ok(UpdateOutcome::UpToDate) => {}
+            Ok(UpdateOutcome::Failed) => error!("CRITICAL UPDATE FAILURE - PLEASE REBOOT DEVICE"),
+            Err(e) => error!("Update cycle failed: {e}"),
+        }
+        sleep_with_jitter(Duration::from_secs(interval)).await;
+    }
+}
 
-    // Backup the Old binary and swap in the new one
-    swap_binaries().await?;
+/// Parses a `--interval <seconds>` flag out of the process args.
+fn parse_interval(args: &[String]) -> Option<u64> {
+    let idx = args.iter().position(|a| a == "--interval")?;
+    args.get(idx + 1)?.parse().ok()
+}
 
-    // Start service with retry + rollback
-    if try_start_with_rollback().await? {
-        info!("Update successful!");
-    } else {
-        error!("CRITICAL UPDATE FAILURE - PLEASE REBOOT DEVICE");
-    }
-    Ok(())
+/// Parses a `--channel <name>` flag out of the process args.
+fn parse_channel(args: &[String]) -> Option<String> {
+    let idx = args.iter().position(|a| a == "--channel")?;
+    args.get(idx + 1).cloned()
 }